@@ -0,0 +1,48 @@
+//! Compiles and runs jq-style filter expressions (via the `jaq-core` /
+//! `jaq-std` interpreter) against decoded events, so events can be selected
+//! or transformed on-device before they're serialized: e.g. an energy-window
+//! cut (`select(.energy > 1000 and .energy < 1500)`) or a field projection,
+//! without post-processing huge raw files.
+
+use jaq_interpret::{Ctx, FilterT, ParseCtx, RcIter, Val};
+
+/// A compiled jq program. Compilation happens once, up front, so a mistake
+/// in `--filter` is reported before the measurement begins rather than
+/// mid-run.
+pub struct Program {
+    filter: jaq_interpret::Filter,
+}
+
+impl Program {
+    pub fn compile(source: &str) -> Result<Program, String> {
+        let mut ctx = ParseCtx::new(Vec::new());
+        ctx.insert_natives(jaq_core::core());
+        ctx.insert_defs(jaq_std::std());
+
+        let (main, errs) = jaq_parse::parse(source, jaq_parse::main());
+        if !errs.is_empty() {
+            return Err(errs.iter().map(|e| e.to_string()).collect::<Vec<_>>().join("; "));
+        }
+        let main = main.ok_or_else(|| "empty filter program".to_string())?;
+
+        let filter = ctx.compile(main);
+        if !ctx.errs.is_empty() {
+            return Err(ctx.errs.iter().map(|(e, _)| e.to_string()).collect::<Vec<_>>().join("; "));
+        }
+        Ok(Program { filter })
+    }
+
+    /// Runs the program against a single decoded event, returning zero or
+    /// more transformed events. Outputs of `false`, `null`, or an empty
+    /// result set drop the event.
+    pub fn run(&self, input: serde_json::Value) -> Vec<serde_json::Value> {
+        let inputs = RcIter::new(core::iter::empty());
+        let ctx = Ctx::new([], &inputs);
+        self.filter
+            .run((ctx, Val::from(input)))
+            .filter_map(|result| result.ok())
+            .map(serde_json::Value::from)
+            .filter(|value| !matches!(value, serde_json::Value::Null | serde_json::Value::Bool(false)))
+            .collect()
+    }
+}
@@ -0,0 +1,23 @@
+//! Parsing for the `--listen` argument, which accepts either a TCP socket
+//! address (`127.0.0.1:9000`) or a Unix domain socket path
+//! (`unix:/run/mbfilter.sock`) so the control program can be driven locally
+//! without opening a TCP port.
+
+use std::error::Error;
+use std::net::SocketAddr;
+use std::path::PathBuf;
+
+/// Where the `server` subcommand should listen.
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl ListenAddr {
+    pub fn parse(value: &str) -> Result<ListenAddr, Box<dyn Error + Send + Sync>> {
+        match value.strip_prefix("unix:") {
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => Ok(ListenAddr::Tcp(value.parse()?)),
+        }
+    }
+}
@@ -25,6 +25,22 @@ use futures_util::FutureExt;
 use futures_util::SinkExt;
 use warp::http;
 
+mod writer;
+use writer::{Writer, WriterConfig};
+
+mod scpi;
+use scpi::Interpreter;
+
+mod listen;
+use listen::ListenAddr;
+
+mod encoder;
+use encoder::{Encoder, Format};
+use std::str::FromStr;
+
+mod filter;
+use filter::Program as FilterProgram;
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
@@ -85,10 +101,19 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
                 .short("l")
                 .long("listen")
                 .value_name("listen")
-                .help("the IP address and port that the server should listen on")
+                .help("the IP address and port that the server should listen on, or unix:<path> for a Unix domain socket")
                 .takes_value(true)
                 .required(true)
-                .index(1)))
+                .index(1))
+            .arg(Arg::with_name("mode")
+                .long("mode")
+                .value_name("mode")
+                .help("protocol to speak on the listening socket")
+                .takes_value(true)
+                .possible_values(&["websocket", "scpi"])
+                .default_value("websocket")))
+        .subcommand(SubCommand::with_name("console")
+            .about("starts a local interactive SCPI command REPL against the filter over stdin/stdout"))
         .subcommand(SubCommand::with_name("start")
             .about("command that starts the measurement. The filter has to be configured to be able to start")
             .arg(Arg::with_name("output file")
@@ -102,10 +127,44 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
             .arg(Arg::with_name("target file size")
                 .short("s")
                 .long("target-file-size")
-                .help("The file size that should be collected before the measurement is automatically stopped")
+                .help("raw hardware bytes to read before the measurement is automatically stopped; \
+                       with --format csv/jsonl or --filter this is not the same as the output file size")
                 .takes_value(true)
                 .required(true)
-                .index(2)))
+                .index(2))
+            .arg(Arg::with_name("backlog")
+                .long("backlog")
+                .value_name("chunks")
+                .help("capacity of the channel between the hardware read loop and the disk writer, in record chunks")
+                .takes_value(true))
+            .arg(Arg::with_name("write buffer size")
+                .long("write-buffer-size")
+                .value_name("bytes")
+                .help("size of the writer's internal buffer in bytes")
+                .takes_value(true))
+            .arg(Arg::with_name("write timeout")
+                .long("write-timeout-ms")
+                .value_name("ms")
+                .help("max time to wait for room in the writer channel before halting the filter and reporting an overflow")
+                .takes_value(true))
+            .arg(Arg::with_name("throttle")
+                .long("throttle-ms")
+                .value_name("ms")
+                .help("if set, the writer sleeps this long after each write to coalesce small writes")
+                .takes_value(true))
+            .arg(Arg::with_name("format")
+                .long("format")
+                .value_name("format")
+                .help("output encoding for decoded peak records")
+                .takes_value(true)
+                .possible_values(&["raw", "csv", "jsonl"])
+                .default_value("raw"))
+            .arg(Arg::with_name("filter")
+                .long("filter")
+                .value_name("jq program")
+                .help("jq-style expression run over each decoded event before it is serialized; \
+                       events producing false/null/empty are dropped (requires --format csv or jsonl)")
+                .takes_value(true)))
         .subcommand(SubCommand::with_name("status")
             .about("command that returns the current state of the hardware filter with the currently loaded configuration"))
         .subcommand(SubCommand::with_name("stop")
@@ -127,49 +186,180 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
 
     // start subcommand
     if let Some(matches) = matches.subcommand_matches("start") {
-        let mut filter = MBFilter::new()?;
+        let filter = Arc::new(Mutex::new(MBFilter::new()?));
         let requested_pc = u64::from_str_radix(matches.value_of("target file size").unwrap(), 10)?;
         let filepath = matches.value_of("output file").unwrap();
         let path = Path::new(filepath);
         let ofile = File::create(&path)?;
-        let mut ofile = BufWriter::new(ofile);
+
+        let mut writer_config = WriterConfig::default();
+        if let Some(backlog) = matches.value_of("backlog") {
+            writer_config.backlog = backlog.parse()?;
+        }
+        if let Some(capacity) = matches.value_of("write buffer size") {
+            writer_config.capacity = capacity.parse()?;
+        }
+        if let Some(timeout_ms) = matches.value_of("write timeout") {
+            writer_config.timeout_ms = timeout_ms.parse()?;
+        }
+        if let Some(throttle_ms) = matches.value_of("throttle") {
+            writer_config.throttle_ms = Some(throttle_ms.parse()?);
+        }
+
+        let format = Format::from_str(matches.value_of("format").unwrap())?;
+        let filter_program = match matches.value_of("filter") {
+            Some(_) if format == Format::Raw => {
+                Err("--filter requires --format csv or jsonl, not raw")?
+            }
+            Some(source) => Some(FilterProgram::compile(source)?),
+            None => None,
+        };
+
+        let mut writer = Writer::spawn(ofile, writer_config);
+        let mut encoder = Encoder::new(format, filter_program);
+
+        {
+            let mut locked_filter = filter.lock().unwrap();
+            match locked_filter.state() {
+                MBFState::Ready => locked_filter.start(),
+                _ => Err(MBError::WrongState)?,
+            }
+        }
+
+        let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(writer_config.backlog.max(1));
+        let hardware_read = spawn_read_task(filter.clone(), tx);
         let mut fc: u64 = 0;
-        match filter.state() {
-            MBFState::Ready => {
-                filter.start();
-                let mut buffer: [u8; 12*2048] = [0; 12*2048];
-                while fc < requested_pc {
-                    let bytes_read = filter.read(&mut buffer)?;
-                    debug!("{} bytes read", bytes_read);
-                    let mut pos = 0;
-                    while pos < (&buffer[..bytes_read]).len() {
-                        let bytes_written = ofile.write(&buffer[pos..bytes_read])?;
-                        pos += bytes_written;
+
+        let mut encode_error = None;
+        let mut dropped_events: u64 = 0;
+        tokio::select! {
+            _ = async {
+                while let Some(chunk) = rx.recv().await {
+                    let raw_len = chunk.len() as u64;
+                    let (encoded, records) = match encoder.encode(&chunk) {
+                        Ok(result) => result,
+                        Err(e) => {
+                            encode_error = Some(e);
+                            break;
+                        }
                     };
-                    fc += bytes_read as u64;
+                    if encoded.is_empty() {
+                        // nothing to write for this chunk: either a partial record
+                        // the encoder is holding for the next chunk, or every event
+                        // was dropped by --filter; the raw bytes still count as read
+                        fc += raw_len;
+                    } else {
+                        match writer.send(encoded, records).await {
+                            Ok(()) => fc += raw_len,
+                            Err(overflow) => {
+                                error!("{}", overflow);
+                                dropped_events = overflow.dropped_records;
+                                break;
+                            }
+                        }
+                    }
+                    if fc >= requested_pc {
+                        break;
+                    }
                 }
-                filter.stop();
-            },
-            _ => Err(MBError::WrongState)?,
+            } => {
+                debug!("measurement loop ended (target reached, overflow, encode error or end of hardware reads), stopping");
+            }
+            _ = shutdown_signal() => {
+                info!("received shutdown signal, stopping measurement early");
+            }
+        }
+
+        if let Ok(mut locked_filter) = filter.lock() {
+            locked_filter.stop();
         }
+        // The blocking read thread may be parked in `tx.blocking_send(...)`
+        // if the channel filled up right as we stopped consuming (e.g. a
+        // small --backlog). It only notices `stop()` on its next `read()`
+        // call, which won't happen until that send unblocks, so keep
+        // draining `rx` until the thread observes the stop and exits.
+        while rx.recv().await.is_some() {}
+        let _ = hardware_read.await;
+
+        let written = writer.close().await?;
+        if let Some(e) = encode_error {
+            Err(e)?;
+        }
+        info!(
+            "wrote {} bytes ({} events) to {}, {} of {} requested bytes collected",
+            written, encoder.events_written() - dropped_events, filepath, fc, requested_pc
+        );
     }
 
     // server subcommand
     if let Some(matches) = matches.subcommand_matches("server") {
         let filter = Arc::new(Mutex::new(MBFilter::new()?));
-        let socket_address: std::net::SocketAddr = matches.value_of("listen").unwrap().parse()?;
-        let hello = warp::path("websocket")
-            .and(warp::query::query())
-            .and(warp::ws())
-            .map(move |config, ws| {
-                ws_handler(filter.clone(), config, ws);
-                ""
-            });
-        warp::serve(hello)
-            .run(socket_address)
-            .await;
+        let listen = ListenAddr::parse(matches.value_of("listen").unwrap())?;
+        match matches.value_of("mode").unwrap() {
+            "scpi" => {
+                tokio::select! {
+                    result = run_scpi_server(filter.clone(), listen) => { result?; }
+                    _ = shutdown_signal() => {
+                        info!("received shutdown signal, stopping SCPI server");
+                    }
+                }
+            }
+            _ => {
+                let ws_filter = filter.clone();
+                let hello = warp::path("websocket")
+                    .and(warp::query::query())
+                    .and(warp::query::query())
+                    .and(warp::ws())
+                    .then(move |config, format_query: FormatQuery, ws| {
+                        ws_handler(ws_filter.clone(), config, format_query, ws)
+                    });
+                match listen {
+                    ListenAddr::Tcp(socket_address) => {
+                        tokio::select! {
+                            _ = warp::serve(hello).run(socket_address) => {}
+                            _ = shutdown_signal() => {
+                                info!("received shutdown signal, stopping server");
+                            }
+                        }
+                    }
+                    ListenAddr::Unix(path) => {
+                        if path.exists() {
+                            std::fs::remove_file(&path)?;
+                        }
+                        let unix_listener = tokio::net::UnixListener::bind(&path)?;
+                        let incoming = tokio_stream::wrappers::UnixListenerStream::new(unix_listener);
+                        tokio::select! {
+                            _ = warp::serve(hello).run_incoming(incoming) => {}
+                            _ = shutdown_signal() => {
+                                info!("received shutdown signal, stopping server");
+                            }
+                        }
+                        let _ = std::fs::remove_file(&path);
+                    }
+                }
+            }
+        }
+        if let Ok(mut locked_filter) = filter.lock() {
+            locked_filter.stop();
+        }
     }
 
+    // console subcommand
+    if let Some(_) = matches.subcommand_matches("console") {
+        let filter = Arc::new(Mutex::new(MBFilter::new()?));
+        let mut interpreter = Interpreter::new(filter);
+        let stdin = std::io::stdin();
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if stdin.read_line(&mut line)? == 0 {
+                break;
+            }
+            if let Some(response) = interpreter.execute(&line) {
+                println!("{}", response);
+            }
+        }
+    }
 
     // stop subcommand
     if let Some(_) = matches.subcommand_matches("stop") {
@@ -187,24 +377,235 @@ async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
     Ok(())
 }
 
-async fn read_task(filter: Arc<Mutex<MBFilter>>, ws: warp::ws::Ws) {
+/// Accepts connections on a TCP or Unix domain socket and speaks line-based
+/// SCPI against a shared `MBFilter`, one `Interpreter` per connection, all
+/// backed by the same filter handle.
+async fn run_scpi_server(
+    filter: Arc<Mutex<MBFilter>>,
+    listen: ListenAddr,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    use tokio::net::{TcpListener, UnixListener};
+
+    match listen {
+        ListenAddr::Tcp(socket_address) => {
+            let listener = TcpListener::bind(socket_address).await?;
+            info!("SCPI server listening on {}", socket_address);
+            loop {
+                let (stream, peer) = listener.accept().await?;
+                spawn_scpi_connection(filter.clone(), stream, peer.to_string());
+            }
+        }
+        ListenAddr::Unix(path) => {
+            if path.exists() {
+                std::fs::remove_file(&path)?;
+            }
+            let listener = UnixListener::bind(&path)?;
+            info!("SCPI server listening on {}", path.display());
+            let result: Result<(), Box<dyn Error + Send + Sync>> = async {
+                loop {
+                    let (stream, _) = listener.accept().await?;
+                    spawn_scpi_connection(filter.clone(), stream, path.display().to_string());
+                }
+            }
+            .await;
+            let _ = std::fs::remove_file(&path);
+            result
+        }
+    }
+}
+
+/// Spawns a task that reads newline-terminated SCPI command lines from
+/// `stream` and writes query responses back, until the connection closes.
+fn spawn_scpi_connection<S>(filter: Arc<Mutex<MBFilter>>, stream: S, peer: String)
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin + Send + 'static,
+{
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+
+    debug!("SCPI client connected: {}", peer);
+    tokio::spawn(async move {
+        let mut interpreter = Interpreter::new(filter);
+        let (reader, mut writer) = tokio::io::split(stream);
+        let mut lines = BufReader::new(reader).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            if let Some(response) = interpreter.execute(&line) {
+                if writer.write_all(format!("{}\n", response).as_bytes()).await.is_err() {
+                    break;
+                }
+            }
+        }
+        debug!("SCPI client disconnected: {}", peer);
+    });
+}
+
+/// Resolves on SIGINT or SIGTERM, so a measurement or server in progress can
+/// be torn down cleanly instead of leaving the FPGA filter running and the
+/// output file half-written.
+async fn shutdown_signal() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => {},
+        _ = sigterm.recv() => {},
+    }
+}
+
+/// Reads 12-byte records from the (blocking) hardware filter on a dedicated
+/// blocking thread and forwards each chunk read to `tx`. Returns once the
+/// filter reports a zero-length read, which is what happens once another
+/// task calls `filter.stop()`, or on a read error or a dropped receiver.
+fn spawn_read_task(
+    filter: Arc<Mutex<MBFilter>>,
+    tx: tokio::sync::mpsc::Sender<Vec<u8>>,
+) -> tokio::task::JoinHandle<()> {
+    tokio::task::spawn_blocking(move || {
+        let mut buffer: [u8; 12 * 2048] = [0; 12 * 2048];
+        loop {
+            let bytes_read = {
+                let mut locked_filter = match filter.lock() {
+                    Ok(locked_filter) => locked_filter,
+                    Err(_) => break,
+                };
+                match locked_filter.read(&mut buffer) {
+                    Ok(bytes_read) => bytes_read,
+                    Err(e) => {
+                        error!("hardware read failed: {}", e);
+                        break;
+                    }
+                }
+            };
+            if bytes_read == 0 {
+                break;
+            }
+            if tx.blocking_send(buffer[..bytes_read].to_vec()).is_err() {
+                break;
+            }
+        }
+    })
+}
+
+/// Drives one websocket connection for the duration of a measurement: feeds
+/// hardware records to the client as binary frames, and stops the filter
+/// once the client disconnects, closes the socket, or sends a "stop" text
+/// frame.
+async fn read_task(
+    filter: Arc<Mutex<MBFilter>>,
+    format: Format,
+    filter_program: Option<FilterProgram>,
+    ws: warp::ws::WebSocket,
+) {
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Vec<u8>>(64);
+    let hardware_read = spawn_read_task(filter.clone(), tx);
+    let mut encoder = Encoder::new(format, filter_program);
+
+    loop {
+        tokio::select! {
+            chunk = rx.recv() => {
+                match chunk {
+                    Some(chunk) => {
+                        let (encoded, _records) = match encoder.encode(&chunk) {
+                            Ok(result) => result,
+                            Err(e) => {
+                                error!("{}", e);
+                                break;
+                            }
+                        };
+                        if !encoded.is_empty() && ws_tx.send(warp::ws::Message::binary(encoded)).await.is_err() {
+                            break;
+                        }
+                    }
+                    None => break,
+                }
+            }
+            message = ws_rx.next() => {
+                let should_stop = match message {
+                    Some(Ok(ref message)) if message.is_close() => true,
+                    Some(Ok(ref message)) if message.is_text() && message.to_str() == Ok("stop") => true,
+                    Some(Ok(_)) => false,
+                    _ => true,
+                };
+                if should_stop {
+                    break;
+                }
+            }
+        }
+    }
+
+    if let Ok(mut locked_filter) = filter.lock() {
+        locked_filter.stop();
+    }
+    // The blocking read thread may be parked in `tx.blocking_send(...)` if
+    // the client was slow enough to fill the channel right as it
+    // disconnected; it only notices `stop()` on its next `read()` call,
+    // which won't happen until that send unblocks, so keep draining `rx`
+    // until the thread observes the stop and exits.
+    while rx.recv().await.is_some() {}
+    let _ = hardware_read.await;
 }
 
+/// Query string companion to `MBConfig` selecting the output encoding (and
+/// optional jq filter expression) for the websocket stream; defaults to raw
+/// records when omitted.
+#[derive(serde::Deserialize)]
+struct FormatQuery {
+    format: Option<String>,
+    filter: Option<String>,
+}
 
-async fn ws_handler(filter: Arc<Mutex<MBFilter>>, config: MBConfig, ws: warp::ws::Ws) -> dyn warp::Reply {
+async fn ws_handler(
+    filter: Arc<Mutex<MBFilter>>,
+    config: MBConfig,
+    format_query: FormatQuery,
+    ws: warp::ws::Ws,
+) -> impl warp::Reply {
     let config = match config.validate() {
         Ok(config) => config,
-        Err(e) => panic!("AAAAH"),
+        Err(e) => {
+            return warp::reply::with_status(format!("invalid configuration: {:?}", e), http::StatusCode::BAD_REQUEST)
+                .into_response();
+        }
+    };
+    let format = match format_query.format.as_deref().unwrap_or("raw").parse::<Format>() {
+        Ok(format) => format,
+        Err(e) => {
+            return warp::reply::with_status(e, http::StatusCode::BAD_REQUEST).into_response();
+        }
+    };
+    let filter_program = match format_query.filter.as_deref() {
+        Some(_) if format == Format::Raw => {
+            return warp::reply::with_status(
+                "filter requires format=csv or format=jsonl, not raw",
+                http::StatusCode::BAD_REQUEST,
+            )
+            .into_response();
+        }
+        Some(source) => match FilterProgram::compile(source) {
+            Ok(program) => Some(program),
+            Err(e) => {
+                return warp::reply::with_status(format!("invalid filter: {}", e), http::StatusCode::BAD_REQUEST)
+                    .into_response();
+            }
+        },
+        None => None,
+    };
+
+    let mut locked_filter = match filter.lock() {
+        Ok(locked_filter) => locked_filter,
+        Err(_) => {
+            return warp::reply::with_status("filter lock poisoned", http::StatusCode::INTERNAL_SERVER_ERROR)
+                .into_response();
+        }
     };
-    let mut locked_filter = filter.try_lock();
-    if let Ok(ref mut unlocked_filter) = locked_filter {
-        match unlocked_filter.state() {
-            MBFState::Ready | MBFState::InvalidParameters => {
-                unlocked_filter.configure(config);
-                //TODO: do websocket things
-                //tokio::task::spawn(read_task(filter.clone(), ws));
-            },
-            _ => panic!("bbb"),//return warp::reply::with_status(format!("Filter already running"), http::status::StatusCode::TERMPORARILY_UNAVAILABLE),
+    match locked_filter.state() {
+        MBFState::Ready | MBFState::InvalidParameters => {
+            locked_filter.configure(config);
+            locked_filter.start();
+            drop(locked_filter);
+            let filter = filter.clone();
+            ws.on_upgrade(move |socket| read_task(filter, format, filter_program, socket)).into_response()
         }
+        _ => warp::reply::with_status("filter already running", http::StatusCode::SERVICE_UNAVAILABLE)
+            .into_response(),
     }
 }
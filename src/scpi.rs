@@ -0,0 +1,151 @@
+//! SCPI-style (Standard Commands for Programmable Instruments) text control
+//! protocol, so this device can be driven like a normal lab instrument by
+//! existing SCPI tooling instead of the ad-hoc websocket query string.
+//!
+//! Commands are newline-terminated ASCII; queries (ending in `?`) produce
+//! exactly one line of response, setters produce none and report failures
+//! via the `SYST:ERR?` error queue instead. The same [`Interpreter`] backs
+//! the SCPI mode of the `server` subcommand and the local interactive REPL.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+
+use moessbauer_filter::{MBConfig, MBFilter, MBFState};
+
+const IDN: &str = "Moessbauer Filter,MBFilter,0,0.1";
+
+/// The five parameters that make up an `MBConfig`, staged individually as
+/// `CONF:K`/`CONF:L`/... setters arrive and re-applied to the filter after
+/// every change.
+#[derive(Clone)]
+struct PendingConfig {
+    k: String,
+    l: String,
+    m: String,
+    pthresh: String,
+    dtime: String,
+}
+
+impl Default for PendingConfig {
+    fn default() -> Self {
+        PendingConfig {
+            k: "0".to_string(),
+            l: "0".to_string(),
+            m: "0".to_string(),
+            pthresh: "0".to_string(),
+            dtime: "0".to_string(),
+        }
+    }
+}
+
+impl PendingConfig {
+    fn build(&self) -> Result<MBConfig, Box<dyn std::error::Error + Send + Sync>> {
+        Ok(MBConfig::new_from_str(&self.k, &self.l, &self.m, &self.pthresh, &self.dtime)?)
+    }
+}
+
+/// Parses and executes SCPI command lines against a shared `MBFilter`.
+pub struct Interpreter {
+    filter: Arc<Mutex<MBFilter>>,
+    pending: PendingConfig,
+    errors: VecDeque<String>,
+}
+
+impl Interpreter {
+    pub fn new(filter: Arc<Mutex<MBFilter>>) -> Interpreter {
+        Interpreter {
+            filter,
+            pending: PendingConfig::default(),
+            errors: VecDeque::new(),
+        }
+    }
+
+    /// Parses and executes a single command line, returning a response line
+    /// for queries, or `None` for setters.
+    pub fn execute(&mut self, line: &str) -> Option<String> {
+        let line = line.trim();
+        if line.is_empty() {
+            return None;
+        }
+        let (head, arg) = match line.split_once(char::is_whitespace) {
+            Some((head, arg)) => (head, arg.trim()),
+            None => (line, ""),
+        };
+        match head.to_ascii_uppercase().as_str() {
+            "*IDN?" => Some(IDN.to_string()),
+            "*RST" => {
+                self.pending = PendingConfig::default();
+                None
+            }
+            "CONF:K" => self.set_param(arg, |p, v| p.k = v),
+            "CONF:L" => self.set_param(arg, |p, v| p.l = v),
+            "CONF:M" => self.set_param(arg, |p, v| p.m = v),
+            "CONF:PTHRESH" | "CONF:PTHR" => self.set_param(arg, |p, v| p.pthresh = v),
+            "CONF:DTIME" | "CONF:DTIM" => self.set_param(arg, |p, v| p.dtime = v),
+            "CONF?" => Some(self.with_filter_query(|filter| format!("{}", filter.configuration()))),
+            "INIT" => {
+                self.with_filter_mut(|filter| match filter.state() {
+                    MBFState::Ready => {
+                        filter.start();
+                        Ok(())
+                    }
+                    _ => Err("filter is not in the Ready state".to_string()),
+                });
+                None
+            }
+            "ABOR" | "ABORT" => {
+                self.with_filter_mut(|filter| {
+                    filter.stop();
+                    Ok(())
+                });
+                None
+            }
+            "STAT?" | "STATUS?" => Some(self.with_filter_query(|filter| format!("{}", filter.state()))),
+            "SYST:ERR?" => Some(
+                self.errors
+                    .pop_front()
+                    .unwrap_or_else(|| "0,\"No error\"".to_string()),
+            ),
+            _ => {
+                self.error(format!("unrecognized command: {}", head));
+                None
+            }
+        }
+    }
+
+    fn set_param(&mut self, arg: &str, apply: impl FnOnce(&mut PendingConfig, String)) -> Option<String> {
+        apply(&mut self.pending, arg.to_string());
+        match self.pending.build() {
+            Ok(config) => self.with_filter_mut(|filter| {
+                filter.configure(config);
+                Ok(())
+            }),
+            Err(e) => self.error(format!("{}", e)),
+        }
+        None
+    }
+
+    fn with_filter_mut(&mut self, f: impl FnOnce(&mut MBFilter) -> Result<(), String>) {
+        let result = match self.filter.lock() {
+            Ok(mut filter) => f(&mut filter),
+            Err(_) => Err("filter lock poisoned".to_string()),
+        };
+        if let Err(e) = result {
+            self.error(e);
+        }
+    }
+
+    fn with_filter_query(&mut self, f: impl FnOnce(&MBFilter) -> String) -> String {
+        match self.filter.lock() {
+            Ok(filter) => f(&filter),
+            Err(_) => {
+                self.error("filter lock poisoned".to_string());
+                String::new()
+            }
+        }
+    }
+
+    fn error(&mut self, message: String) {
+        self.errors.push_back(format!("-1,\"{}\"", message));
+    }
+}
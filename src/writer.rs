@@ -0,0 +1,159 @@
+//! Decoupled, back-pressured disk writer.
+//!
+//! Reading from the FPGA FIFO and flushing to disk are split into two
+//! concurrently running halves connected by a bounded channel: the hardware
+//! read loop pushes chunks of raw records (or their CSV/JSON encoding) in,
+//! and a dedicated Tokio task
+//! owns the `BufWriter<File>` and drains them. This keeps a slow disk from
+//! ever blocking the read side directly; instead the channel fills up and,
+//! once it has been full for longer than `timeout_ms`, the caller is told to
+//! give up rather than risk overflowing the FIFO.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::time::Duration;
+
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::task::JoinHandle;
+
+/// Size in bytes of a single hardware record, as produced by `MBFilter::read`.
+pub const RECORD_SIZE: usize = 12;
+
+/// Tuning knobs for a [`Writer`].
+#[derive(Debug, Clone, Copy)]
+pub struct WriterConfig {
+    /// Capacity of the outer channel, in chunks.
+    pub backlog: usize,
+    /// Size in bytes of the `BufWriter`'s internal buffer.
+    pub capacity: usize,
+    /// Maximum time to wait for room in the channel before reporting overflow.
+    pub timeout_ms: u64,
+    /// If set, the writer task sleeps this long after each write to coalesce
+    /// small writes instead of flushing every chunk immediately.
+    pub throttle_ms: Option<u64>,
+}
+
+impl Default for WriterConfig {
+    fn default() -> Self {
+        WriterConfig {
+            backlog: 1024,
+            capacity: 12 * 2048,
+            timeout_ms: 1000,
+            throttle_ms: None,
+        }
+    }
+}
+
+/// Reported when the writer could not keep up and the caller should stop the
+/// filter instead of blocking hardware reads indefinitely.
+#[derive(Debug)]
+pub struct Overflow {
+    pub dropped_bytes: u64,
+    pub dropped_records: u64,
+}
+
+impl fmt::Display for Overflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "writer channel full for longer than the configured timeout, \
+             dropped {} record(s) ({} bytes)",
+            self.dropped_records, self.dropped_bytes
+        )
+    }
+}
+
+impl std::error::Error for Overflow {}
+
+/// Owns a `BufWriter<File>` on a dedicated Tokio task and accepts chunks of
+/// raw 12-byte records or encoded output over a bounded channel, so reading
+/// from hardware and
+/// flushing to disk can run concurrently.
+pub struct Writer {
+    sender: mpsc::Sender<Vec<u8>>,
+    timeout: Duration,
+    dropped_bytes: u64,
+    dropped_records: u64,
+    handle: JoinHandle<std::io::Result<u64>>,
+}
+
+impl Writer {
+    /// Spawns the writer task and returns a handle to send chunks to it.
+    pub fn spawn(file: File, config: WriterConfig) -> Writer {
+        let (sender, receiver) = mpsc::channel(config.backlog.max(1));
+        let handle = tokio::spawn(Writer::run(file, config, receiver));
+        Writer {
+            sender,
+            timeout: Duration::from_millis(config.timeout_ms),
+            dropped_bytes: 0,
+            dropped_records: 0,
+            handle,
+        }
+    }
+
+    async fn run(
+        file: File,
+        config: WriterConfig,
+        mut receiver: mpsc::Receiver<Vec<u8>>,
+    ) -> std::io::Result<u64> {
+        let mut writer = BufWriter::with_capacity(config.capacity, file);
+        let throttle = config.throttle_ms.map(Duration::from_millis);
+        let mut written: u64 = 0;
+        while let Some(chunk) = receiver.recv().await {
+            writer.write_all(&chunk)?;
+            written += chunk.len() as u64;
+            if let Some(delay) = throttle {
+                tokio::time::sleep(delay).await;
+            }
+        }
+        writer.flush()?;
+        Ok(written)
+    }
+
+    /// Hands a chunk to the writer task, along with the number of records
+    /// it contains. `records` is taken as a parameter rather than derived
+    /// from `chunk.len()`, since callers may hand over already-encoded
+    /// CSV/JSON text whose byte length has no fixed relationship to
+    /// `RECORD_SIZE`. Tries to enqueue the chunk immediately; if the
+    /// channel is full, waits up to `timeout_ms` for room before giving up
+    /// and returning [`Overflow`].
+    pub async fn send(&mut self, chunk: Vec<u8>, records: u64) -> Result<(), Overflow> {
+        let len = chunk.len() as u64;
+        match self.sender.try_send(chunk) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Closed(_)) => {
+                self.dropped_bytes += len;
+                self.dropped_records += records;
+                Err(self.overflow())
+            }
+            Err(TrySendError::Full(chunk)) => {
+                match tokio::time::timeout(self.timeout, self.sender.send(chunk)).await {
+                    Ok(Ok(())) => Ok(()),
+                    _ => {
+                        self.dropped_bytes += len;
+                        self.dropped_records += records;
+                        Err(self.overflow())
+                    }
+                }
+            }
+        }
+    }
+
+    fn overflow(&self) -> Overflow {
+        Overflow {
+            dropped_bytes: self.dropped_bytes,
+            dropped_records: self.dropped_records,
+        }
+    }
+
+    /// Closes the channel and waits for the writer task to flush and drop
+    /// the underlying file, returning the total number of bytes written.
+    pub async fn close(self) -> std::io::Result<u64> {
+        drop(self.sender);
+        self.handle
+            .await
+            .unwrap_or_else(|e| panic!("writer task panicked: {}", e))
+    }
+}